@@ -0,0 +1,91 @@
+//! Generates `$OUT_DIR/instrs.rs` from `instructions.in`: the `OPCODE_*`
+//! constants, an `Opcode` enum, and an `Opcode::from_u8` match skeleton.
+//! `src/vm.rs` pulls it in with `include!`, so adding an instruction is a
+//! one-line table edit instead of a hand-written constant that can drift
+//! out of sync with the dispatch `match` in `decode_instruction`.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+struct Instr {
+    mnemonic: String,
+    opcode: u8,
+    operands: String,
+}
+
+fn parse_table(src: &str) -> Vec<Instr> {
+    src.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let mnemonic = fields.next().expect("missing mnemonic").to_string();
+            let opcode = fields
+                .next()
+                .expect("missing opcode")
+                .parse()
+                .expect("opcode must be a u8");
+            let operands = fields.next().unwrap_or("none").to_string();
+            Instr {
+                mnemonic,
+                opcode,
+                operands,
+            }
+        })
+        .collect()
+}
+
+fn camel_case(mnemonic: &str) -> String {
+    let mut chars = mnemonic.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().collect::<String>() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=instructions.in");
+
+    let table = fs::read_to_string("instructions.in").expect("reading instructions.in");
+    let instrs = parse_table(&table);
+
+    let mut out = String::new();
+    out.push_str("// Generated by build.rs from instructions.in. Do not edit by hand.\n\n");
+
+    for instr in &instrs {
+        out.push_str(&format!(
+            "pub(crate) const OPCODE_{}: u8 = {};\n",
+            instr.mnemonic.to_uppercase(),
+            instr.opcode
+        ));
+    }
+
+    out.push_str("\n/// An instruction opcode, generated from `instructions.in`.\n");
+    out.push_str("#[derive(Debug, Clone, Copy, PartialEq, Eq)]\n");
+    out.push_str("pub(crate) enum Opcode {\n");
+    for instr in &instrs {
+        out.push_str(&format!("    {},\n", camel_case(&instr.mnemonic)));
+    }
+    out.push_str("}\n");
+
+    out.push_str("\nimpl Opcode {\n");
+    out.push_str("    /// Maps a 5-bit opcode field (an instruction byte's top 5 bits) to its `Opcode`, mirroring the `OPCODE_*` constants.\n");
+    out.push_str("    pub(crate) fn from_u8(b: u8) -> Option<Self> {\n");
+    out.push_str("        match b {\n");
+    for instr in &instrs {
+        out.push_str(&format!(
+            "            OPCODE_{} => Some(Opcode::{}), // operands: {}\n",
+            instr.mnemonic.to_uppercase(),
+            camel_case(&instr.mnemonic),
+            instr.operands
+        ));
+    }
+    out.push_str("            _ => None,\n");
+    out.push_str("        }\n");
+    out.push_str("    }\n");
+    out.push_str("}\n");
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
+    fs::write(Path::new(&out_dir).join("instrs.rs"), out).expect("writing instrs.rs");
+}
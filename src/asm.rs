@@ -0,0 +1,352 @@
+//! A textual assembler for Rain VM bytecode.
+//!
+//! The assembly format has one instruction or label per line, with register
+//! operands written `r0`..`r31` and immediates written as decimal integers.
+//! Comments start with `;` and run to the end of the line. Labels are
+//! written `name:` on their own line and referenced by `bnz` as branch
+//! targets; they resolve to the absolute byte offset `bnz` expects.
+//!
+//! Supported mnemonics: `mov`, `add`, `bnz`, `load`, `store`, `ecall`, `halt`.
+//! `load`/`store` take a base register, an offset immediate, and a data
+//! register: `load dest, base, offset` assembles `dest = mem[base + offset]`,
+//! and `store base, offset, src` assembles `mem[base + offset] = src`.
+//! `ecall number` raises a trap with `number` as an immediate.
+//!
+//! The newer arithmetic instructions (`sub`, `mul`, `divrem`, `fadd`, `fsub`,
+//! `fmul`, `fdiv`, `rdtime`) have no textual syntax yet; `assemble` rejects
+//! them with `UnknownMnemonic` until this module is extended to cover them.
+
+use std::collections::HashMap;
+
+use failure::Fail;
+
+use crate::version;
+
+/// An assembly error.
+#[derive(Fail, Debug)]
+pub enum AsmError {
+    /// An unknown mnemonic.
+    #[fail(display = "unknown mnemonic: {:?}", mnemonic)]
+    UnknownMnemonic { mnemonic: String },
+
+    /// A line did not have enough operands for its mnemonic.
+    #[fail(display = "missing operand in {:?}", line)]
+    MissingOperand { line: String },
+
+    /// A register operand was malformed or out of range.
+    #[fail(display = "invalid register: {:?}", text)]
+    InvalidRegister { text: String },
+
+    /// An immediate operand was not a valid integer.
+    #[fail(display = "invalid immediate: {:?}", text)]
+    InvalidImmediate { text: String },
+
+    /// A branch target referenced an undefined label.
+    #[fail(display = "undefined label: {:?}", label)]
+    UndefinedLabel { label: String },
+
+    /// The same label was defined more than once.
+    #[fail(display = "duplicate label: {:?}", label)]
+    DuplicateLabel { label: String },
+}
+
+// 1-byte version header plus the 4-byte initial memory size that
+// `Machine::execute_bytes` expects right after it.
+const HEADER_LEN: u32 = 5;
+
+enum Instr {
+    MovReg { src: u8, dest: u8 },
+    MovImm { dest: u8, imm: u32 },
+    AddReg { src1: u8, src2: u8, dest: u8 },
+    AddImm { src: u8, dest: u8, imm: u32 },
+    Bnz { reg: u8, label: String },
+    Load { base: u8, dest: u8, offset: u32 },
+    Store { base: u8, src: u8, offset: u32 },
+    Ecall { number: u32 },
+    Halt,
+}
+
+impl Instr {
+    fn len(&self) -> u32 {
+        match self {
+            Instr::MovReg { .. } => 2,
+            Instr::MovImm { .. } => 6,
+            Instr::AddReg { .. } => 3,
+            Instr::AddImm { .. } => 6,
+            Instr::Bnz { .. } => 6,
+            Instr::Load { .. } => 7,
+            Instr::Store { .. } => 7,
+            Instr::Ecall { .. } => 5,
+            Instr::Halt => 1,
+        }
+    }
+}
+
+/// Assembles `src` into a Rain VM bytecode stream, ready for `execute_bytes`.
+pub fn assemble(src: &str) -> Result<Vec<u8>, AsmError> {
+    let mut instrs = Vec::new();
+    let mut labels = HashMap::new();
+    let mut offset = HEADER_LEN;
+
+    for line in src.lines() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(label) = line.strip_suffix(':') {
+            let label = label.trim().to_string();
+            if labels.insert(label.clone(), offset).is_some() {
+                return Err(AsmError::DuplicateLabel { label });
+            }
+            continue;
+        }
+        let instr = parse_instr(line)?;
+        offset += instr.len();
+        instrs.push(instr);
+    }
+
+    let mut out = Vec::with_capacity(offset as usize);
+    out.push(version::BYTE_VERSION);
+    out.extend_from_slice(&0u32.to_be_bytes());
+    for instr in &instrs {
+        emit(instr, &labels, &mut out)?;
+    }
+    Ok(out)
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find(';') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+fn parse_instr(line: &str) -> Result<Instr, AsmError> {
+    let mut parts = line.splitn(2, char::is_whitespace);
+    let mnemonic = parts.next().unwrap_or("");
+    let rest = parts.next().unwrap_or("");
+    let operands: Vec<&str> = rest.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+
+    let operand = |i: usize| -> Result<&str, AsmError> {
+        operands
+            .get(i)
+            .copied()
+            .ok_or_else(|| AsmError::MissingOperand {
+                line: line.to_string(),
+            })
+    };
+
+    match mnemonic {
+        "mov" => {
+            let dest = parse_reg(operand(0)?)?;
+            let src = operand(1)?;
+            match parse_reg(src) {
+                Ok(src) => Ok(Instr::MovReg { src, dest }),
+                Err(_) => Ok(Instr::MovImm {
+                    dest,
+                    imm: parse_imm(src)?,
+                }),
+            }
+        }
+        "add" => {
+            let dest = parse_reg(operand(0)?)?;
+            let src1 = operand(1)?;
+            let src2 = operand(2)?;
+            match (parse_reg(src1), parse_reg(src2)) {
+                (Ok(src1), Ok(src2)) => Ok(Instr::AddReg { src1, src2, dest }),
+                _ => Ok(Instr::AddImm {
+                    src: parse_reg(src1)?,
+                    dest,
+                    imm: parse_imm(src2)?,
+                }),
+            }
+        }
+        "bnz" => Ok(Instr::Bnz {
+            reg: parse_reg(operand(0)?)?,
+            label: operand(1)?.to_string(),
+        }),
+        "load" => Ok(Instr::Load {
+            dest: parse_reg(operand(0)?)?,
+            base: parse_reg(operand(1)?)?,
+            offset: parse_imm(operand(2)?)?,
+        }),
+        "store" => Ok(Instr::Store {
+            base: parse_reg(operand(0)?)?,
+            offset: parse_imm(operand(1)?)?,
+            src: parse_reg(operand(2)?)?,
+        }),
+        "ecall" => Ok(Instr::Ecall {
+            number: parse_imm(operand(0)?)?,
+        }),
+        "halt" => Ok(Instr::Halt),
+        _ => Err(AsmError::UnknownMnemonic {
+            mnemonic: mnemonic.to_string(),
+        }),
+    }
+}
+
+fn parse_reg(text: &str) -> Result<u8, AsmError> {
+    let err = || AsmError::InvalidRegister {
+        text: text.to_string(),
+    };
+    let n: u8 = text.strip_prefix('r').ok_or_else(err)?.parse().map_err(|_| err())?;
+    if n > 0b11111 {
+        return Err(err());
+    }
+    Ok(n)
+}
+
+fn parse_imm(text: &str) -> Result<u32, AsmError> {
+    text.parse::<u32>()
+        .or_else(|_| text.parse::<i32>().map(|n| n as u32))
+        .map_err(|_| AsmError::InvalidImmediate {
+            text: text.to_string(),
+        })
+}
+
+fn emit(instr: &Instr, labels: &HashMap<String, u32>, out: &mut Vec<u8>) -> Result<(), AsmError> {
+    match *instr {
+        Instr::MovReg { src, dest } => {
+            out.push((crate::vm::OPCODE_MOVE << 3) | (src >> 3));
+            out.push(((src & 0b111) << 5) | (dest & 0b11111));
+        }
+        Instr::MovImm { dest, imm } => {
+            out.push((crate::vm::OPCODE_MOVE << 3) | 0b100);
+            out.push(dest & 0b11111);
+            out.extend_from_slice(&imm.to_be_bytes());
+        }
+        Instr::AddReg { src1, src2, dest } => {
+            out.push((crate::vm::OPCODE_ADD << 3) | (src1 >> 3));
+            out.push(((src1 & 0b111) << 5) | (src2 & 0b11111));
+            out.push(dest << 3);
+        }
+        Instr::AddImm { src, dest, imm } => {
+            out.push((crate::vm::OPCODE_ADD << 3) | 0b100 | (src >> 3));
+            out.push(((src & 0b111) << 5) | (dest & 0b11111));
+            out.extend_from_slice(&imm.to_be_bytes());
+        }
+        Instr::Bnz { reg, ref label } => {
+            let target = *labels
+                .get(label)
+                .ok_or_else(|| AsmError::UndefinedLabel {
+                    label: label.clone(),
+                })?;
+            out.push(crate::vm::OPCODE_BNZ << 3);
+            out.push(reg & 0b11111);
+            out.extend_from_slice(&target.to_be_bytes());
+        }
+        Instr::Load { base, dest, offset } => {
+            out.push(crate::vm::OPCODE_LOAD << 3);
+            out.push(base & 0b11111);
+            out.push(dest & 0b11111);
+            out.extend_from_slice(&offset.to_be_bytes());
+        }
+        Instr::Store { base, src, offset } => {
+            out.push(crate::vm::OPCODE_STORE << 3);
+            out.push(base & 0b11111);
+            out.push(src & 0b11111);
+            out.extend_from_slice(&offset.to_be_bytes());
+        }
+        Instr::Ecall { number } => {
+            out.push(crate::vm::OPCODE_ECALL << 3);
+            out.extend_from_slice(&number.to_be_bytes());
+        }
+        Instr::Halt => out.push(crate::vm::OPCODE_HALT << 3),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::discriminant;
+
+    use super::*;
+
+    #[test]
+    fn test_unknown_mnemonic() {
+        let err = assemble("frobnicate r0\n").unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&AsmError::UnknownMnemonic {
+                mnemonic: String::new()
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_operand() {
+        let err = assemble("mov r0\n").unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&AsmError::MissingOperand { line: String::new() })
+        );
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let err = assemble("mov r99, 1\n").unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&AsmError::InvalidRegister { text: String::new() })
+        );
+    }
+
+    #[test]
+    fn test_invalid_immediate() {
+        let err = assemble("ecall nope\n").unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&AsmError::InvalidImmediate { text: String::new() })
+        );
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let err = assemble("bnz r0, nowhere\n").unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&AsmError::UndefinedLabel { label: String::new() })
+        );
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let err = assemble("loop:\nhalt\nloop:\nhalt\n").unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&AsmError::DuplicateLabel { label: String::new() })
+        );
+    }
+
+    #[test]
+    fn test_round_trip_load_store_ecall_label_bnz() {
+        let src = "\
+            mov r0, 0\n\
+            mov r1, 7\n\
+            store r0, 0, r1\n\
+            load r2, r0, 0\n\
+            loop:\n\
+            ecall 1\n\
+            bnz r2, loop\n\
+            halt\n\
+        ";
+        let bytes = assemble(src).unwrap();
+        let instrs = crate::disasm::disassemble(&bytes).unwrap();
+
+        assert_eq!(instrs.len(), 7);
+        assert!(matches!(instrs[0].1, crate::vm::Instruction::MovImm { .. }));
+        assert!(matches!(instrs[1].1, crate::vm::Instruction::MovImm { .. }));
+        assert!(matches!(instrs[2].1, crate::vm::Instruction::Store { .. }));
+        assert!(matches!(instrs[3].1, crate::vm::Instruction::Load { .. }));
+        assert!(matches!(instrs[4].1, crate::vm::Instruction::Ecall { number: 1 }));
+        match &instrs[5].1 {
+            crate::vm::Instruction::Bnz { target, .. } => {
+                // The label resolves to the `ecall` byte offset, right after
+                // the `load`/`store` preceding it.
+                assert_eq!(*target, instrs[4].0 as u32);
+            }
+            other => panic!("expected Bnz, got {:?}", other),
+        }
+        assert!(matches!(instrs[6].1, crate::vm::Instruction::Halt));
+    }
+}
@@ -0,0 +1,115 @@
+//! A disassembler for Rain VM bytecode.
+//!
+//! Walks the same decoding logic `Machine::execute_bytes` uses
+//! (`vm::decode_instruction`), so the two can never drift apart, but
+//! yields each instruction instead of running it. Useful for debugging
+//! programs and for verifying `asm::assemble` output.
+
+use crate::version;
+use crate::vm::{self, ExecutionError, Instruction};
+
+/// Decodes `bytes` into its instructions, paired with their byte offsets.
+pub fn disassemble(bytes: &[u8]) -> Result<Vec<(usize, Instruction)>, ExecutionError> {
+    let mut iter = bytes.iter();
+    match iter.next() {
+        None => return Err(ExecutionError::MissingVersion),
+        Some(&b) => {
+            if b != version::BYTE_VERSION {
+                return Err(ExecutionError::VersionMismatch { version: b });
+            }
+        }
+    }
+    // Skip the 4-byte initial memory size that follows the version byte.
+    for _ in 0..4 {
+        iter.next().ok_or(ExecutionError::TruncatedU32)?;
+    }
+
+    let mut instrs = Vec::new();
+    loop {
+        let offset = bytes.len() - iter.as_slice().len();
+        if iter.as_slice().is_empty() {
+            break;
+        }
+        let instr = vm::decode_instruction(&mut iter)?;
+        let is_halt = matches!(instr, Instruction::Halt);
+        instrs.push((offset, instr));
+        if is_halt {
+            break;
+        }
+    }
+    Ok(instrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(initial_memory_size: u32) -> Vec<u8> {
+        let mut b = vec![version::BYTE_VERSION];
+        b.extend_from_slice(&initial_memory_size.to_be_bytes());
+        b
+    }
+
+    fn mov_imm(dest: u8, imm: u32) -> Vec<u8> {
+        let mut b = vec![(crate::vm::OPCODE_MOVE << 3) | 0b100, dest];
+        b.extend_from_slice(&imm.to_be_bytes());
+        b
+    }
+
+    fn halt() -> Vec<u8> {
+        vec![crate::vm::OPCODE_HALT << 3]
+    }
+
+    #[test]
+    fn test_disassemble_skips_header() {
+        let mut bytes = header(0);
+        bytes.extend(mov_imm(0, 42));
+        bytes.extend(halt());
+
+        let instrs = disassemble(&bytes).unwrap();
+        // The first decoded offset is right after the 5-byte version+length
+        // header, proving the header itself was skipped rather than decoded
+        // as instruction bytes.
+        assert_eq!(instrs[0].0, 5);
+        match &instrs[0].1 {
+            Instruction::MovImm { dest, imm } => {
+                assert_eq!(dest.0, 0);
+                assert_eq!(*imm, 42);
+            }
+            other => panic!("expected MovImm, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_disassemble_halt_terminates_early() {
+        let mut bytes = header(0);
+        bytes.extend(mov_imm(0, 42));
+        bytes.extend(halt());
+        // Bytes after the halt must never be reached or decoded.
+        bytes.push(0xff);
+
+        let instrs = disassemble(&bytes).unwrap();
+        assert_eq!(instrs.len(), 2);
+        assert!(matches!(instrs[1].1, Instruction::Halt));
+    }
+
+    #[test]
+    fn test_disassemble_version_mismatch() {
+        let bytes = vec![version::BYTE_VERSION + 1, 0, 0, 0, 0];
+        let err = disassemble(&bytes).unwrap_err();
+        assert_eq!(
+            std::mem::discriminant(&err),
+            std::mem::discriminant(&ExecutionError::VersionMismatch { version: 0 })
+        );
+    }
+
+    #[test]
+    fn test_disassemble_asm_round_trip() {
+        let bytes = crate::asm::assemble("mov r0, 1\nadd r0, r0, r0\nhalt\n").unwrap();
+        let instrs = disassemble(&bytes).unwrap();
+        assert_eq!(instrs.len(), 3);
+        assert!(matches!(instrs[0].1, Instruction::MovImm { .. }));
+        assert!(matches!(instrs[1].1, Instruction::AddReg { .. }));
+        assert!(matches!(instrs[2].1, Instruction::Halt));
+    }
+}
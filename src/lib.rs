@@ -0,0 +1,18 @@
+//! Rain VM: A virtual machine for Rain ML.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(test, feature(test))]
+
+#[macro_use]
+extern crate failure_derive;
+extern crate failure;
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod asm;
+#[cfg(feature = "std")]
+pub mod disasm;
+pub mod version;
+pub mod vm;
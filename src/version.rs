@@ -2,14 +2,22 @@
 //! The first is the byte version.
 //! The second is the dominant version.
 
+#[cfg(feature = "std")]
 use std::collections::HashMap;
 
 /// The current byte version.
-pub const BYTE_VERSION: u8 = 1;
+///
+/// Bumped from `1` to `2` when the bytecode format grew a 4-byte initial
+/// memory size header right after the version byte, so that version-1
+/// streams fail the version check instead of having their first four
+/// instruction bytes misread as a memory size.
+pub const BYTE_VERSION: u8 = 2;
 
 /// Returns a map from byte versions to dominant versions.
+#[cfg(feature = "std")]
 pub fn version_map() -> HashMap<u8, String> {
     let mut m = HashMap::new();
     m.insert(1, "0.1.0".to_string());
+    m.insert(2, "0.2.0".to_string());
     m
 }
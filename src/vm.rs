@@ -1,10 +1,17 @@
 //! Rain VM: A virtual machine for Rain ML.
 
-use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
 use std::fs;
+#[cfg(feature = "std")]
 use std::io;
+#[cfg(feature = "std")]
 use std::io::Read;
 
+#[cfg(feature = "std")]
 use failure::Error;
 
 use crate::version;
@@ -21,8 +28,12 @@ pub enum ExecutionError {
     VersionMismatch { version: u8 },
 
     /// File-open error.
+    #[cfg(feature = "std")]
     #[fail(display = "opening file {:?}: {}", filename, error)]
-    FileOpen { filename: String, error: io::Error },
+    FileOpen {
+        filename: std::string::String,
+        error: io::Error,
+    },
 
     /// Reached the unexpected end of program.
     #[fail(display = "unexpected end of program")]
@@ -47,9 +58,133 @@ pub enum ExecutionError {
     /// Nowhere to jump.
     #[fail(display = "nowhere to jump to: {:?}", address)]
     NowhereToJump { address: u32 },
+
+    /// Accessed memory out of bounds.
+    #[fail(
+        display = "memory access fault: address {} is out of bounds (memory size: {})",
+        address, len
+    )]
+    MemoryAccessFault { address: u32, len: u32 },
+
+    /// An `ecall` trap was raised, but no handler was installed to service it.
+    #[fail(display = "unhandled trap: {}", number)]
+    UnhandledTrap { number: u32 },
+
+    /// Integer division (`DIVREM`) with a zero divisor.
+    #[fail(display = "divide by zero")]
+    DivideByZero,
+
+    /// The program's requested initial memory size exceeds `MAX_INITIAL_MEMORY_SIZE`.
+    #[fail(
+        display = "requested initial memory size {} exceeds the {}-byte limit",
+        requested, max
+    )]
+    MemoryTooLarge { requested: u32, max: u32 },
+
+    /// The instruction budget installed by `Machine::with_limit` ran out.
+    #[fail(display = "cycle limit exceeded")]
+    CycleLimitExceeded,
+}
+
+/// A rounding mode selector for the floating-point arithmetic instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to the nearest representable `f32`, ties to even.
+    Nearest,
+    /// Round toward zero (truncate).
+    TowardZero,
+    /// Round toward positive infinity.
+    Up,
+    /// Round toward negative infinity.
+    Down,
+}
+
+impl RoundingMode {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => RoundingMode::Nearest,
+            1 => RoundingMode::TowardZero,
+            2 => RoundingMode::Up,
+            _ => RoundingMode::Down,
+        }
+    }
+}
+
+/// Rounds `x` to the nearest `f32`, then adjusts by at most one ULP to honor
+/// `mode`. This emulates rounding-mode-controlled conversion; Rust's `as`
+/// cast and arithmetic always round to nearest, and stable Rust exposes no
+/// way to select the hardware's rounding mode directly.
+fn round_f32(x: f64, mode: RoundingMode) -> f32 {
+    let nearest = x as f32;
+    match mode {
+        RoundingMode::Nearest => nearest,
+        RoundingMode::TowardZero => {
+            if (nearest as f64).abs() > x.abs() {
+                if nearest > 0.0 {
+                    next_down(nearest)
+                } else {
+                    next_up(nearest)
+                }
+            } else {
+                nearest
+            }
+        }
+        RoundingMode::Up => {
+            if (nearest as f64) < x {
+                next_up(nearest)
+            } else {
+                nearest
+            }
+        }
+        RoundingMode::Down => {
+            if (nearest as f64) > x {
+                next_down(nearest)
+            } else {
+                nearest
+            }
+        }
+    }
+}
+
+/// The next `f32` representable value toward positive infinity.
+fn next_up(v: f32) -> f32 {
+    if v.is_nan() || v == f32::INFINITY {
+        return v;
+    }
+    if v == 0.0 {
+        return f32::from_bits(1);
+    }
+    let bits = v.to_bits();
+    f32::from_bits(if v > 0.0 { bits + 1 } else { bits - 1 })
+}
+
+/// The next `f32` representable value toward negative infinity.
+fn next_down(v: f32) -> f32 {
+    -next_up(-v)
+}
+
+/// The action a `Trap` handler requests after servicing an `ecall`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrapAction {
+    /// Resume execution at the instruction following the `ecall`.
+    Continue,
+    /// Stop execution, as if `OPCODE_HALT` had been reached.
+    Halt,
+    /// Abort execution; the trap could not be serviced.
+    Fault,
+}
+
+/// A handler for `ecall` traps raised by a running program.
+///
+/// Embedders install a `Trap` to implement host calls (print, read, time, ...)
+/// without baking them into the core execution loop.
+pub trait Trap {
+    /// Services a trap raised with the given number, with mutable access to the register file.
+    fn handle(&mut self, number: u32, regs: &mut File) -> TrapAction;
 }
 
 /// Executes a file.
+#[cfg(feature = "std")]
 pub fn execute_file(filename: &str) -> Result<u32, Error> {
     let f = fs::File::open(filename).map_err(|e| ExecutionError::FileOpen {
         filename: filename.to_string(),
@@ -67,13 +202,30 @@ pub fn execute_bytes(v: Vec<u8>) -> Result<u32, ExecutionError> {
     m.get(Reg(0)).map_err(|_| ExecutionError::NoResult)
 }
 
-#[derive(PartialEq, Eq, Hash, Debug)]
-pub struct Reg(u8);
+#[derive(PartialEq, Eq, Debug)]
+pub struct Reg(pub u8);
 
-struct File(HashMap<Reg, u32>);
+/// The number of addressable registers; `REGISTER_WIDTH` caps a register index to this range.
+const NUM_REGISTERS: usize = 32;
 
-struct Machine {
+/// The register file, exposed so that a `Trap` handler can read and write registers.
+///
+/// Tracks which registers have been written in a bitmask, so reading a
+/// register before it's ever been written faults with `NoSuchRegister`
+/// instead of silently yielding 0.
+pub struct File {
+    values: [u32; NUM_REGISTERS],
+    written: u32,
+}
+
+pub struct Machine {
     file: File,
+    memory: Vec<u8>,
+    trap: Option<Box<dyn Trap>>,
+    /// Elapsed instruction count, readable from a program via `OPCODE_RDTIME`.
+    cycles: u32,
+    /// Remaining instruction budget; execution faults with `CycleLimitExceeded` at zero.
+    limit: Option<u32>,
 }
 
 // Shifts 3 bits.
@@ -81,18 +233,278 @@ const SHIFT_OPCODE: u8 = 3;
 
 const REGISTER_WIDTH: u8 = 0b11111;
 
-const OPCODE_MOVE: u8 = 0;
-const OPCODE_HALT: u8 = 1;
-const OPCODE_ADD: u8 = 2;
-const OPCODE_BNZ: u8 = 3;
+/// The largest initial memory size a program may request, so that a crafted
+/// header can't make `execute_bytes` allocate an attacker-chosen amount of
+/// memory before a single instruction has run.
+const MAX_INITIAL_MEMORY_SIZE: u32 = 1 << 24;
+
+// The `OPCODE_*` constants, the `Opcode` enum, and `Opcode::from_u8` below are
+// generated by `build.rs` from `instructions.in`. `decode_instruction` matches
+// on `Opcode` itself (not the raw `OPCODE_*` bits), so adding a row to
+// `instructions.in` without adding a matching arm is a compile error instead
+// of a silent `NoSuchInstruction` at runtime.
+include!(concat!(env!("OUT_DIR"), "/instrs.rs"));
+
+/// A single decoded instruction, shared by the interpreter and the disassembler.
+#[derive(Debug)]
+pub enum Instruction {
+    /// "Move register" instruction.
+    MovReg { src: Reg, dest: Reg },
+    /// "Move immediate" instruction.
+    MovImm { dest: Reg, imm: u32 },
+    /// "Add register" instruction. Arithmetic overflow is ignored.
+    AddReg { src1: Reg, src2: Reg, dest: Reg },
+    /// "Add immediate" instruction. Arithmetic overflow is ignored.
+    AddImm { src: Reg, dest: Reg, imm: u32 },
+    /// "Branch if not zero" instruction.
+    Bnz { reg: Reg, target: u32 },
+    /// "Load" instruction: `dest = memory[base + offset]`.
+    Load { base: Reg, dest: Reg, offset: u32 },
+    /// "Store" instruction: `memory[base + offset] = src`.
+    Store { base: Reg, src: Reg, offset: u32 },
+    /// "Ecall" instruction: raises a trap with the given number.
+    Ecall { number: u32 },
+    /// "Subtract register" instruction. Arithmetic overflow is ignored.
+    SubReg { src1: Reg, src2: Reg, dest: Reg },
+    /// "Subtract immediate" instruction. Arithmetic overflow is ignored.
+    SubImm { src: Reg, dest: Reg, imm: u32 },
+    /// "Multiply register" instruction. Arithmetic overflow is ignored.
+    MulReg { src1: Reg, src2: Reg, dest: Reg },
+    /// "Multiply immediate" instruction. Arithmetic overflow is ignored.
+    MulImm { src: Reg, dest: Reg, imm: u32 },
+    /// "Divide with remainder" instruction: `quotient, remainder = dividend /, % divisor`.
+    DivRem {
+        dividend: Reg,
+        divisor: Reg,
+        quotient: Reg,
+        remainder: Reg,
+    },
+    /// IEEE-754 `f32` addition.
+    FAdd {
+        src1: Reg,
+        src2: Reg,
+        dest: Reg,
+        rounding: RoundingMode,
+    },
+    /// IEEE-754 `f32` subtraction.
+    FSub {
+        src1: Reg,
+        src2: Reg,
+        dest: Reg,
+        rounding: RoundingMode,
+    },
+    /// IEEE-754 `f32` multiplication.
+    FMul {
+        src1: Reg,
+        src2: Reg,
+        dest: Reg,
+        rounding: RoundingMode,
+    },
+    /// IEEE-754 `f32` division.
+    FDiv {
+        src1: Reg,
+        src2: Reg,
+        dest: Reg,
+        rounding: RoundingMode,
+    },
+    /// "Read time" instruction: writes the elapsed instruction count into `dest`.
+    RdTime { dest: Reg },
+    /// "Halt" instruction.
+    Halt,
+}
+
+/// Decodes a single instruction from `iter`, without executing it.
+///
+/// This is the single source of truth for the byte layout of each
+/// instruction; both `Machine::execute_bytes` and `disasm::disassemble`
+/// decode through this function so they can never drift apart.
+pub fn decode_instruction<'a, T>(iter: &mut T) -> Result<Instruction, ExecutionError>
+where
+    T: Iterator<Item = &'a u8>,
+{
+    let b = *must_next(iter)?;
+    match Opcode::from_u8(b >> SHIFT_OPCODE) {
+        Some(Opcode::Move) => {
+            if b & 0b100 == 0 {
+                let b2 = *must_next(iter)?;
+                let lower = b2 >> 5;
+                let upper = (b & 0b11) << 3;
+                let src = Reg(lower | upper);
+                let dest = Reg(b2 & 0b11111);
+                Ok(Instruction::MovReg { src, dest })
+            } else {
+                let dest = Reg(*must_next(iter)? & 0b11111);
+                let imm = decode_u32(iter)?;
+                Ok(Instruction::MovImm { dest, imm })
+            }
+        }
+        Some(Opcode::Halt) => Ok(Instruction::Halt),
+        Some(Opcode::Add) => {
+            let bits = b & 0b11;
+            if b & 0b100 == 0 {
+                let b2 = *must_next(iter)?;
+                let lower = b2 >> 5;
+                let upper = bits << 3;
+                let src1 = Reg(lower | upper);
+                let src2 = Reg(b2 & 0b11111);
+                let dest = Reg(*must_next(iter)? >> 3);
+                Ok(Instruction::AddReg { src1, src2, dest })
+            } else {
+                let b2 = *must_next(iter)?;
+                let lower = b2 >> 5;
+                let upper = bits << 3;
+                let src = Reg(lower | upper);
+                let dest = Reg(b2 & 0b11111);
+                let imm = decode_u32(iter)?;
+                Ok(Instruction::AddImm { src, dest, imm })
+            }
+        }
+        Some(Opcode::Bnz) => {
+            let reg = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let target = decode_u32(iter)?;
+            Ok(Instruction::Bnz { reg, target })
+        }
+        Some(Opcode::Load) => {
+            let base = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let offset = decode_u32(iter)?;
+            Ok(Instruction::Load { base, dest, offset })
+        }
+        Some(Opcode::Store) => {
+            let base = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let src = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let offset = decode_u32(iter)?;
+            Ok(Instruction::Store { base, src, offset })
+        }
+        Some(Opcode::Ecall) => {
+            let number = decode_u32(iter)?;
+            Ok(Instruction::Ecall { number })
+        }
+        Some(Opcode::Sub) => {
+            if b & 0b100 == 0 {
+                let src1 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                let src2 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                Ok(Instruction::SubReg { src1, src2, dest })
+            } else {
+                let src = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                let imm = decode_u32(iter)?;
+                Ok(Instruction::SubImm { src, dest, imm })
+            }
+        }
+        Some(Opcode::Mul) => {
+            if b & 0b100 == 0 {
+                let src1 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                let src2 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                Ok(Instruction::MulReg { src1, src2, dest })
+            } else {
+                let src = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+                let imm = decode_u32(iter)?;
+                Ok(Instruction::MulImm { src, dest, imm })
+            }
+        }
+        Some(Opcode::Divrem) => {
+            let dividend = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let divisor = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let quotient = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let remainder = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            Ok(Instruction::DivRem {
+                dividend,
+                divisor,
+                quotient,
+                remainder,
+            })
+        }
+        Some(Opcode::Fadd) => {
+            let rounding = RoundingMode::from_bits(b);
+            let src1 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let src2 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            Ok(Instruction::FAdd {
+                src1,
+                src2,
+                dest,
+                rounding,
+            })
+        }
+        Some(Opcode::Fsub) => {
+            let rounding = RoundingMode::from_bits(b);
+            let src1 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let src2 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            Ok(Instruction::FSub {
+                src1,
+                src2,
+                dest,
+                rounding,
+            })
+        }
+        Some(Opcode::Fmul) => {
+            let rounding = RoundingMode::from_bits(b);
+            let src1 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let src2 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            Ok(Instruction::FMul {
+                src1,
+                src2,
+                dest,
+                rounding,
+            })
+        }
+        Some(Opcode::Fdiv) => {
+            let rounding = RoundingMode::from_bits(b);
+            let src1 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let src2 = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            Ok(Instruction::FDiv {
+                src1,
+                src2,
+                dest,
+                rounding,
+            })
+        }
+        Some(Opcode::Rdtime) => {
+            let dest = Reg(*must_next(iter)? & REGISTER_WIDTH);
+            Ok(Instruction::RdTime { dest })
+        }
+        None => Err(ExecutionError::NoSuchInstruction {
+            opcode: b >> SHIFT_OPCODE,
+        }),
+    }
+}
 
 impl Machine {
-    fn new() -> Self {
+    /// Creates a machine with no `Trap` handler and no instruction budget.
+    /// Chain `with_handler`/`with_limit` to configure either or both.
+    pub fn new() -> Self {
         Machine {
-            file: File(HashMap::new()),
+            file: File::new(),
+            memory: Vec::new(),
+            trap: None,
+            cycles: 0,
+            limit: None,
         }
     }
 
+    /// Installs a `Trap` handler, used to service `ecall` instructions. Composes with `with_limit`.
+    pub fn with_handler(mut self, trap: Box<dyn Trap>) -> Self {
+        self.trap = Some(trap);
+        self
+    }
+
+    /// Sets an instruction budget: execution faults with `CycleLimitExceeded` after `limit`
+    /// instructions. Composes with `with_handler`, so an embedder sandboxing untrusted bytecode
+    /// can install both a trap handler and a budget on the same machine.
+    ///
+    /// Bounds execution of untrusted bytecode against runaway loops (e.g. a spinning `bnz`).
+    pub fn with_limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
     fn get(&self, r: Reg) -> Result<u32, ExecutionError> {
         self.file
             .get(&r)
@@ -114,129 +526,278 @@ impl Machine {
                 }
             }
         }
+        let initial_memory_size = decode_u32(&mut iter)?;
+        if initial_memory_size > MAX_INITIAL_MEMORY_SIZE {
+            return Err(ExecutionError::MemoryTooLarge {
+                requested: initial_memory_size,
+                max: MAX_INITIAL_MEMORY_SIZE,
+            });
+        }
+        self.memory = vec![0; initial_memory_size as usize];
         loop {
-            let b = *must_next(&mut iter)?;
-            match b >> SHIFT_OPCODE {
-                OPCODE_MOVE => {
-                    // Move.
-                    if b & 0b100 == 0 {
-                        self.mov_reg(&mut iter, b & 0b11)?;
-                    } else {
-                        self.mov_imm(&mut iter)?;
-                    }
+            if let Some(limit) = self.limit {
+                if limit == 0 {
+                    return Err(ExecutionError::CycleLimitExceeded);
                 }
-                OPCODE_HALT => return Ok(()),
-                OPCODE_ADD => {
-                    // Add.
-                    let bits = b & 0b11;
-                    if b & 0b100 == 0 {
-                        self.add_reg(&mut iter, bits)?;
-                    } else {
-                        self.add_imm(&mut iter, bits)?;
-                    }
+                self.limit = Some(limit - 1);
+            }
+            self.cycles = self.cycles.wrapping_add(1);
+            match decode_instruction(&mut iter)? {
+                Instruction::MovReg { src, dest } => {
+                    let w = self.get(src)?;
+                    self.insert(dest, w);
                 }
-                OPCODE_BNZ => {
-                    if let Some(w) = self.bnz(&mut iter)? {
-                        if v.len() <= w as usize {
-                            return Err(ExecutionError::NowhereToJump { address: w });
+                Instruction::MovImm { dest, imm } => self.insert(dest, imm),
+                Instruction::AddReg { src1, src2, dest } => {
+                    let v1 = self.get(src1)?;
+                    let v2 = self.get(src2)?;
+                    self.insert(dest, v1.wrapping_add(v2));
+                }
+                Instruction::AddImm { src, dest, imm } => {
+                    let w = self.get(src)?;
+                    self.insert(dest, w.wrapping_add(imm));
+                }
+                Instruction::Bnz { reg, target } => {
+                    let w = self.get(reg)?;
+                    if w != 0 {
+                        if v.len() <= target as usize {
+                            return Err(ExecutionError::NowhereToJump { address: target });
                         }
-                        iter = v.iter().skip(w as usize);
+                        iter = v.iter().skip(target as usize);
                     }
                 }
-                b => return Err(ExecutionError::NoSuchInstruction { opcode: b }),
+                Instruction::Load { base, dest, offset } => {
+                    let address = self.get(base)?.wrapping_add(offset);
+                    let w = self.load_u32(address)?;
+                    self.insert(dest, w);
+                }
+                Instruction::Store { base, src, offset } => {
+                    let address = self.get(base)?.wrapping_add(offset);
+                    let w = self.get(src)?;
+                    self.store_u32(address, w)?;
+                }
+                Instruction::Ecall { number } => match self.ecall(number)? {
+                    TrapAction::Continue => {}
+                    TrapAction::Halt => return Ok(()),
+                    TrapAction::Fault => return Err(ExecutionError::UnhandledTrap { number }),
+                },
+                Instruction::SubReg { src1, src2, dest } => self.sub_reg(src1, src2, dest)?,
+                Instruction::SubImm { src, dest, imm } => self.sub_imm(src, dest, imm)?,
+                Instruction::MulReg { src1, src2, dest } => self.mul_reg(src1, src2, dest)?,
+                Instruction::MulImm { src, dest, imm } => self.mul_imm(src, dest, imm)?,
+                Instruction::DivRem {
+                    dividend,
+                    divisor,
+                    quotient,
+                    remainder,
+                } => self.divrem(dividend, divisor, quotient, remainder)?,
+                Instruction::FAdd {
+                    src1,
+                    src2,
+                    dest,
+                    rounding,
+                } => self.fadd(src1, src2, dest, rounding)?,
+                Instruction::FSub {
+                    src1,
+                    src2,
+                    dest,
+                    rounding,
+                } => self.fsub(src1, src2, dest, rounding)?,
+                Instruction::FMul {
+                    src1,
+                    src2,
+                    dest,
+                    rounding,
+                } => self.fmul(src1, src2, dest, rounding)?,
+                Instruction::FDiv {
+                    src1,
+                    src2,
+                    dest,
+                    rounding,
+                } => self.fdiv(src1, src2, dest, rounding)?,
+                Instruction::RdTime { dest } => self.insert(dest, self.cycles),
+                Instruction::Halt => return Ok(()),
             }
         }
     }
 
-    /// "Move register" instruction.
-    /// The parameter `extra_bits` is assumed to be a two-bit integer.
-    fn mov_reg<'a, T>(&mut self, iter: &mut T, extra_bits: u8) -> Result<(), ExecutionError>
-    where
-        T: Iterator<Item = &'a u8>,
-    {
-        let b = must_next(iter)?;
-        let lower = b >> 5;
-        let upper = extra_bits << 3;
-        let src = Reg(lower | upper);
+    /// Reads a big-endian 32-bit word from memory, faulting if it doesn't fit.
+    fn load_u32(&self, address: u32) -> Result<u32, ExecutionError> {
+        let start = address as usize;
+        let end = start
+            .checked_add(4)
+            .filter(|&end| end <= self.memory.len())
+            .ok_or(ExecutionError::MemoryAccessFault {
+                address,
+                len: self.memory.len() as u32,
+            })?;
+        let b = &self.memory[start..end];
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
 
-        let v = self.get(src)?;
-        self.insert(Reg(b & 0b11111), v);
+    /// Writes a big-endian 32-bit word to memory, faulting if it doesn't fit.
+    fn store_u32(&mut self, address: u32, w: u32) -> Result<(), ExecutionError> {
+        let start = address as usize;
+        let end = start
+            .checked_add(4)
+            .filter(|&end| end <= self.memory.len())
+            .ok_or(ExecutionError::MemoryAccessFault {
+                address,
+                len: self.memory.len() as u32,
+            })?;
+        self.memory[start..end].copy_from_slice(&w.to_be_bytes());
         Ok(())
     }
 
-    /// "Move immediate" instruction.
-    fn mov_imm<'a, T>(&mut self, iter: &mut T) -> Result<(), ExecutionError>
-    where
-        T: Iterator<Item = &'a u8>,
-    {
-        let b = must_next(iter)? & 0b11111;
-        let r = Reg(b);
-        let w = decode_u32(iter)?;
-        self.insert(r, w);
+    /// "Subtract register" instruction. Arithmetic overflow is ignored.
+    fn sub_reg(&mut self, src1: Reg, src2: Reg, dest: Reg) -> Result<(), ExecutionError> {
+        let v1 = self.get(src1)?;
+        let v2 = self.get(src2)?;
+        self.insert(dest, v1.wrapping_sub(v2));
         Ok(())
     }
 
-    /// "Add register" instruction.
-    /// The parameter `extra_bits` is assumed to be a two-bit integer.
-    /// Arithmetic overflow is ignored.
-    fn add_reg<'a, T>(&mut self, iter: &mut T, extra_bits: u8) -> Result<(), ExecutionError>
-    where
-        T: Iterator<Item = &'a u8>,
-    {
-        let b = must_next(iter)?;
-        let lower = b >> 5;
-        let upper = extra_bits << 3;
-        let src1 = Reg(lower | upper);
-        let src2 = Reg(b & 0b11111);
-
-        let dest = Reg(must_next(iter)? >> 3);
+    /// "Subtract immediate" instruction. Arithmetic overflow is ignored.
+    fn sub_imm(&mut self, src: Reg, dest: Reg, imm: u32) -> Result<(), ExecutionError> {
+        let v = self.get(src)?;
+        self.insert(dest, v.wrapping_sub(imm));
+        Ok(())
+    }
+
+    /// "Multiply register" instruction. Arithmetic overflow is ignored.
+    fn mul_reg(&mut self, src1: Reg, src2: Reg, dest: Reg) -> Result<(), ExecutionError> {
         let v1 = self.get(src1)?;
         let v2 = self.get(src2)?;
-        self.insert(dest, v1.wrapping_add(v2));
+        self.insert(dest, v1.wrapping_mul(v2));
         Ok(())
     }
 
-    /// "Add immediate" instruction.
-    /// The parameter `extra_bits` is assumed to be a two-bit integer.
-    /// Arithmetic overflow is ignored.
-    fn add_imm<'a, T>(&mut self, iter: &mut T, extra_bits: u8) -> Result<(), ExecutionError>
-    where
-        T: Iterator<Item = &'a u8>,
-    {
-        let b = must_next(iter)?;
-        let lower = b >> 5;
-        let upper = extra_bits << 3;
-        let src = Reg(lower | upper);
-
-        let w = decode_u32(iter)?;
+    /// "Multiply immediate" instruction. Arithmetic overflow is ignored.
+    fn mul_imm(&mut self, src: Reg, dest: Reg, imm: u32) -> Result<(), ExecutionError> {
         let v = self.get(src)?;
-        self.insert(Reg(b & 0b11111), v.wrapping_add(w));
+        self.insert(dest, v.wrapping_mul(imm));
         Ok(())
     }
 
-    /// "Branch if not zero" instruction.
-    fn bnz<'a, T>(&mut self, iter: &mut T) -> Result<Option<u32>, ExecutionError>
-    where
-        T: Iterator<Item = &'a u8>,
-    {
-        let r = Reg(must_next(iter)? & REGISTER_WIDTH);
-        let w = self.get(r)?;
-        let v = decode_u32(iter)?;
-        if w == 0 {
-            Ok(None)
-        } else {
-            Ok(Some(v))
+    /// "Divide with remainder" instruction.
+    fn divrem(
+        &mut self,
+        dividend: Reg,
+        divisor: Reg,
+        quotient: Reg,
+        remainder: Reg,
+    ) -> Result<(), ExecutionError> {
+        let n = self.get(dividend)?;
+        let d = self.get(divisor)?;
+        if d == 0 {
+            return Err(ExecutionError::DivideByZero);
+        }
+        self.insert(quotient, n / d);
+        self.insert(remainder, n % d);
+        Ok(())
+    }
+
+    /// IEEE-754 `f32` addition, honoring the instruction's rounding mode.
+    fn fadd(
+        &mut self,
+        src1: Reg,
+        src2: Reg,
+        dest: Reg,
+        rounding: RoundingMode,
+    ) -> Result<(), ExecutionError> {
+        let a = f32::from_bits(self.get(src1)?);
+        let b = f32::from_bits(self.get(src2)?);
+        let w = round_f32(a as f64 + b as f64, rounding);
+        self.insert(dest, w.to_bits());
+        Ok(())
+    }
+
+    /// IEEE-754 `f32` subtraction, honoring the instruction's rounding mode.
+    fn fsub(
+        &mut self,
+        src1: Reg,
+        src2: Reg,
+        dest: Reg,
+        rounding: RoundingMode,
+    ) -> Result<(), ExecutionError> {
+        let a = f32::from_bits(self.get(src1)?);
+        let b = f32::from_bits(self.get(src2)?);
+        let w = round_f32(a as f64 - b as f64, rounding);
+        self.insert(dest, w.to_bits());
+        Ok(())
+    }
+
+    /// IEEE-754 `f32` multiplication, honoring the instruction's rounding mode.
+    fn fmul(
+        &mut self,
+        src1: Reg,
+        src2: Reg,
+        dest: Reg,
+        rounding: RoundingMode,
+    ) -> Result<(), ExecutionError> {
+        let a = f32::from_bits(self.get(src1)?);
+        let b = f32::from_bits(self.get(src2)?);
+        let w = round_f32(a as f64 * b as f64, rounding);
+        self.insert(dest, w.to_bits());
+        Ok(())
+    }
+
+    /// IEEE-754 `f32` division, honoring the instruction's rounding mode.
+    fn fdiv(
+        &mut self,
+        src1: Reg,
+        src2: Reg,
+        dest: Reg,
+        rounding: RoundingMode,
+    ) -> Result<(), ExecutionError> {
+        let a = f32::from_bits(self.get(src1)?);
+        let b = f32::from_bits(self.get(src2)?);
+        let w = round_f32(a as f64 / b as f64, rounding);
+        self.insert(dest, w.to_bits());
+        Ok(())
+    }
+
+    /// "Ecall" instruction.
+    /// Suspends execution and hands control to the installed `Trap` handler, if any.
+    fn ecall(&mut self, number: u32) -> Result<TrapAction, ExecutionError> {
+        match self.trap.take() {
+            None => Err(ExecutionError::UnhandledTrap { number }),
+            Some(mut trap) => {
+                let action = trap.handle(number, &mut self.file);
+                self.trap = Some(trap);
+                Ok(action)
+            }
         }
     }
 }
 
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
+}
+
 impl File {
-    fn get(&self, r: &Reg) -> Option<u32> {
-        self.0.get(r).cloned()
+    fn new() -> Self {
+        File {
+            values: [0; NUM_REGISTERS],
+            written: 0,
+        }
     }
 
-    fn insert(&mut self, r: Reg, w: u32) {
-        self.0.insert(r, w);
+    pub fn get(&self, r: &Reg) -> Option<u32> {
+        let i = r.0 as usize;
+        if i >= NUM_REGISTERS || self.written & (1 << i) == 0 {
+            return None;
+        }
+        self.values.get(i).copied()
+    }
+
+    pub fn insert(&mut self, r: Reg, w: u32) {
+        if let Some(slot) = self.values.get_mut(r.0 as usize) {
+            *slot = w;
+            self.written |= 1 << r.0;
+        }
     }
 }
 
@@ -260,6 +821,9 @@ where
     iter.next().ok_or(ExecutionError::UnexpectedEndOfProgram)
 }
 
+#[cfg(test)]
+extern crate test;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -307,4 +871,321 @@ mod tests {
     fn bench_decode_u32_2(b: &mut Bencher) {
         b.iter(|| decode_u32(&mut [255; 4].iter()));
     }
+
+    fn header(initial_memory_size: u32) -> Vec<u8> {
+        let mut prog = vec![version::BYTE_VERSION];
+        prog.extend_from_slice(&initial_memory_size.to_be_bytes());
+        prog
+    }
+
+    fn mov_imm(dest: u8, imm: u32) -> Vec<u8> {
+        let mut b = vec![(OPCODE_MOVE << 3) | 0b100, dest & REGISTER_WIDTH];
+        b.extend_from_slice(&imm.to_be_bytes());
+        b
+    }
+
+    fn load(base: u8, dest: u8, offset: u32) -> Vec<u8> {
+        let mut b = vec![OPCODE_LOAD << 3, base & REGISTER_WIDTH, dest & REGISTER_WIDTH];
+        b.extend_from_slice(&offset.to_be_bytes());
+        b
+    }
+
+    fn divrem(dividend: u8, divisor: u8, quotient: u8, remainder: u8) -> Vec<u8> {
+        vec![
+            OPCODE_DIVREM << 3,
+            dividend & REGISTER_WIDTH,
+            divisor & REGISTER_WIDTH,
+            quotient & REGISTER_WIDTH,
+            remainder & REGISTER_WIDTH,
+        ]
+    }
+
+    fn ecall(number: u32) -> Vec<u8> {
+        let mut b = vec![OPCODE_ECALL << 3];
+        b.extend_from_slice(&number.to_be_bytes());
+        b
+    }
+
+    fn rdtime(dest: u8) -> Vec<u8> {
+        vec![OPCODE_RDTIME << 3, dest & REGISTER_WIDTH]
+    }
+
+    fn halt() -> Vec<u8> {
+        vec![OPCODE_HALT << 3]
+    }
+
+    fn sub_reg(src1: u8, src2: u8, dest: u8) -> Vec<u8> {
+        vec![OPCODE_SUB << 3, src1 & REGISTER_WIDTH, src2 & REGISTER_WIDTH, dest & REGISTER_WIDTH]
+    }
+
+    fn sub_imm(src: u8, dest: u8, imm: u32) -> Vec<u8> {
+        let mut b = vec![(OPCODE_SUB << 3) | 0b100, src & REGISTER_WIDTH, dest & REGISTER_WIDTH];
+        b.extend_from_slice(&imm.to_be_bytes());
+        b
+    }
+
+    fn mul_reg(src1: u8, src2: u8, dest: u8) -> Vec<u8> {
+        vec![OPCODE_MUL << 3, src1 & REGISTER_WIDTH, src2 & REGISTER_WIDTH, dest & REGISTER_WIDTH]
+    }
+
+    fn mul_imm(src: u8, dest: u8, imm: u32) -> Vec<u8> {
+        let mut b = vec![(OPCODE_MUL << 3) | 0b100, src & REGISTER_WIDTH, dest & REGISTER_WIDTH];
+        b.extend_from_slice(&imm.to_be_bytes());
+        b
+    }
+
+    fn fdiv(rounding: u8, src1: u8, src2: u8, dest: u8) -> Vec<u8> {
+        vec![
+            (OPCODE_FDIV << 3) | (rounding & 0b11),
+            src1 & REGISTER_WIDTH,
+            src2 & REGISTER_WIDTH,
+            dest & REGISTER_WIDTH,
+        ]
+    }
+
+    #[test]
+    fn test_memory_access_fault() {
+        let mut prog = header(0);
+        prog.extend(mov_imm(0, 0));
+        prog.extend(load(0, 1, 0));
+        prog.extend(halt());
+
+        let err = Machine::new().execute_bytes(prog).unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&ExecutionError::MemoryAccessFault { address: 0, len: 0 })
+        );
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let mut prog = header(0);
+        prog.extend(mov_imm(0, 1));
+        prog.extend(mov_imm(1, 0));
+        prog.extend(divrem(0, 1, 2, 3));
+        prog.extend(halt());
+
+        let err = Machine::new().execute_bytes(prog).unwrap_err();
+        assert_eq!(discriminant(&err), discriminant(&ExecutionError::DivideByZero));
+    }
+
+    #[test]
+    fn test_unhandled_trap() {
+        let mut prog = header(0);
+        prog.extend(ecall(42));
+        prog.extend(halt());
+
+        let err = Machine::new().execute_bytes(prog).unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&ExecutionError::UnhandledTrap { number: 42 })
+        );
+    }
+
+    #[test]
+    fn test_cycle_limit_exceeded() {
+        let mut prog = header(0);
+        prog.extend(mov_imm(0, 1));
+        prog.extend(halt());
+
+        let err = Machine::new()
+            .with_limit(1)
+            .execute_bytes(prog)
+            .unwrap_err();
+        assert_eq!(discriminant(&err), discriminant(&ExecutionError::CycleLimitExceeded));
+    }
+
+    #[test]
+    fn test_rdtime() {
+        let mut prog = header(0);
+        prog.extend(rdtime(0));
+        prog.extend(halt());
+
+        let mut m = Machine::new();
+        m.execute_bytes(prog).unwrap();
+        assert_eq!(m.get(Reg(0)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_memory_too_large() {
+        let prog = header(MAX_INITIAL_MEMORY_SIZE + 1);
+
+        let err = Machine::new().execute_bytes(prog).unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&ExecutionError::MemoryTooLarge { requested: 0, max: 0 })
+        );
+    }
+
+    #[test]
+    fn test_no_such_register_before_write() {
+        let mut prog = header(0);
+        prog.extend(load(0, 1, 0));
+        prog.extend(halt());
+
+        let err = Machine::new().execute_bytes(prog).unwrap_err();
+        assert_eq!(
+            discriminant(&err),
+            discriminant(&ExecutionError::NoSuchRegister { reg: Reg(0) })
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_asm_round_trip() {
+        let prog = crate::asm::assemble("mov r0, 42\nhalt\n").unwrap();
+        assert_eq!(execute_bytes(prog).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_sub_reg() {
+        let mut prog = header(0);
+        prog.extend(mov_imm(0, 10));
+        prog.extend(mov_imm(1, 3));
+        prog.extend(sub_reg(0, 1, 2));
+        prog.extend(halt());
+
+        let mut m = Machine::new();
+        m.execute_bytes(prog).unwrap();
+        assert_eq!(m.get(Reg(2)).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_sub_imm() {
+        let mut prog = header(0);
+        prog.extend(mov_imm(0, 10));
+        prog.extend(sub_imm(0, 1, 3));
+        prog.extend(halt());
+
+        let mut m = Machine::new();
+        m.execute_bytes(prog).unwrap();
+        assert_eq!(m.get(Reg(1)).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_mul_reg() {
+        let mut prog = header(0);
+        prog.extend(mov_imm(0, 6));
+        prog.extend(mov_imm(1, 7));
+        prog.extend(mul_reg(0, 1, 2));
+        prog.extend(halt());
+
+        let mut m = Machine::new();
+        m.execute_bytes(prog).unwrap();
+        assert_eq!(m.get(Reg(2)).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_mul_imm() {
+        let mut prog = header(0);
+        prog.extend(mov_imm(0, 6));
+        prog.extend(mul_imm(0, 1, 7));
+        prog.extend(halt());
+
+        let mut m = Machine::new();
+        m.execute_bytes(prog).unwrap();
+        assert_eq!(m.get(Reg(1)).unwrap(), 42);
+    }
+
+    // `round_f32` only ever nudges the nearest-rounded result by one ULP, so a
+    // single division rarely makes all four modes disagree at once. These two
+    // dividends are chosen so that, between them, every mode diverges from
+    // `Nearest` at least once: 1.0/3.0 rounds up to the nearest `f32`, so
+    // `TowardZero`/`Down` pull back by a ULP while `Up` agrees with `Nearest`;
+    // 7.0/10.0 rounds down to the nearest `f32`, so `Up` pushes forward by a
+    // ULP while `TowardZero`/`Down` agree with `Nearest`.
+    #[test]
+    fn test_fdiv_rounding_modes() {
+        const NEAREST: u8 = 0;
+        const TOWARD_ZERO: u8 = 1;
+        const UP: u8 = 2;
+        const DOWN: u8 = 3;
+
+        let mut prog = header(0);
+        prog.extend(mov_imm(0, 1.0f32.to_bits()));
+        prog.extend(mov_imm(1, 3.0f32.to_bits()));
+        prog.extend(fdiv(NEAREST, 0, 1, 2));
+        prog.extend(fdiv(TOWARD_ZERO, 0, 1, 3));
+        prog.extend(fdiv(UP, 0, 1, 4));
+        prog.extend(fdiv(DOWN, 0, 1, 5));
+
+        prog.extend(mov_imm(10, 7.0f32.to_bits()));
+        prog.extend(mov_imm(11, 10.0f32.to_bits()));
+        prog.extend(fdiv(NEAREST, 10, 11, 12));
+        prog.extend(fdiv(TOWARD_ZERO, 10, 11, 13));
+        prog.extend(fdiv(UP, 10, 11, 14));
+        prog.extend(fdiv(DOWN, 10, 11, 15));
+        prog.extend(halt());
+
+        let mut m = Machine::new();
+        m.execute_bytes(prog).unwrap();
+
+        let get_f32 = |m: &Machine, r: u8| f32::from_bits(m.get(Reg(r)).unwrap());
+
+        // 1.0 / 3.0: Nearest and Up agree; TowardZero and Down pull back a ULP.
+        let nearest = get_f32(&m, 2);
+        let toward_zero = get_f32(&m, 3);
+        let up = get_f32(&m, 4);
+        let down = get_f32(&m, 5);
+        assert_eq!(nearest, up);
+        assert_eq!(toward_zero, down);
+        assert_ne!(nearest, toward_zero);
+        assert_eq!(nearest, 1.0f32 / 3.0f32);
+        assert_eq!(toward_zero, next_down(nearest));
+
+        // 7.0 / 10.0: Nearest, TowardZero, and Down agree; Up pushes a ULP forward.
+        let nearest = get_f32(&m, 12);
+        let toward_zero = get_f32(&m, 13);
+        let up = get_f32(&m, 14);
+        let down = get_f32(&m, 15);
+        assert_eq!(nearest, toward_zero);
+        assert_eq!(nearest, down);
+        assert_ne!(nearest, up);
+        assert_eq!(nearest, 7.0f32 / 10.0f32);
+        assert_eq!(up, next_up(nearest));
+    }
+
+    #[test]
+    fn test_trap_continue_resumes_and_mutates_file() {
+        struct ContinueTrap;
+
+        impl Trap for ContinueTrap {
+            fn handle(&mut self, number: u32, regs: &mut File) -> TrapAction {
+                regs.insert(Reg(9), number);
+                TrapAction::Continue
+            }
+        }
+
+        let mut prog = header(0);
+        prog.extend(ecall(7));
+        prog.extend(mov_imm(0, 99));
+        prog.extend(halt());
+
+        let mut m = Machine::new().with_handler(Box::new(ContinueTrap));
+        m.execute_bytes(prog).unwrap();
+        assert_eq!(m.get(Reg(0)).unwrap(), 99);
+        assert_eq!(m.get(Reg(9)).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_trap_halt_stops_execution() {
+        struct HaltingTrap;
+
+        impl Trap for HaltingTrap {
+            fn handle(&mut self, number: u32, regs: &mut File) -> TrapAction {
+                regs.insert(Reg(9), number);
+                TrapAction::Halt
+            }
+        }
+
+        let mut prog = header(0);
+        prog.extend(ecall(3));
+        prog.extend(mov_imm(0, 99));
+        prog.extend(halt());
+
+        let mut m = Machine::new().with_handler(Box::new(HaltingTrap));
+        m.execute_bytes(prog).unwrap();
+        assert_eq!(m.get(Reg(9)).unwrap(), 3);
+        assert!(m.get(Reg(0)).is_err());
+    }
 }